@@ -1,7 +1,7 @@
 use wasm_bindgen::prelude::*;
 use wasm_bindgen::{JsCast, intern};
 use js_sys::Reflect;
-use web_sys::{HtmlElement, Element, Node, Window, History, Document, Text, Comment, DomTokenList, CssStyleSheet, CssStyleDeclaration, HtmlStyleElement, CssRule};
+use web_sys::{HtmlElement, Element, Node, Window, History, Document, Text, Comment, DomTokenList, CssStyleSheet, CssStyleDeclaration, HtmlStyleElement, CssRule, Url, CustomEvent, CustomEventInit};
 use crate::utils::UnwrapJsExt;
 
 
@@ -32,17 +32,52 @@ pub(crate) fn current_url() -> String {
     WINDOW.with(|w| w.location().href().unwrap_js())
 }
 
+// Relative URLs are resolved against the document's base URL, just like the history API does
 #[track_caller]
-pub(crate) fn go_to_url(url: &str) {
+pub(crate) fn resolve_url(url: &str) -> Option<Url> {
+    let base = DOCUMENT.with(|d| d.base_uri().unwrap_js()).unwrap_or_else(current_url);
+    Url::new_with_base(url, &base).ok()
+}
+
+#[track_caller]
+pub(crate) fn assign_location(url: &str) {
+    WINDOW.with(|w| w.location().assign(url).unwrap_js());
+}
+
+#[track_caller]
+pub(crate) fn replace_location(url: &str) {
+    WINDOW.with(|w| w.location().replace(url).unwrap_js());
+}
+
+#[track_caller]
+pub(crate) fn go_to_url(url: &str, state: &JsValue) {
     HISTORY.with(|h| {
-        h.push_state_with_url(&JsValue::NULL, "", Some(url)).unwrap_js();
+        h.push_state_with_url(state, "", Some(url)).unwrap_js();
     });
 }
 
 #[track_caller]
-pub(crate) fn replace_url(url: &str) {
+pub(crate) fn replace_url(url: &str, state: &JsValue) {
     HISTORY.with(|h| {
-        h.replace_state_with_url(&JsValue::NULL, "", Some(url)).unwrap_js();
+        h.replace_state_with_url(state, "", Some(url)).unwrap_js();
+    });
+}
+
+#[track_caller]
+pub(crate) fn history_state() -> JsValue {
+    HISTORY.with(|h| h.state().unwrap_js())
+}
+
+#[track_caller]
+pub(crate) fn dispatch_window_event(name: &str, detail: &JsValue) {
+    let init = CustomEventInit::new();
+    // TODO use set_detail after bumping the web-sys version
+    set_property(&init, "detail", detail);
+
+    let event = CustomEvent::new_with_event_init_dict(name, &init).unwrap_js();
+
+    WINDOW.with(|w| {
+        w.dispatch_event(&event).unwrap_js();
     });
 }
 
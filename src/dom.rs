@@ -1577,7 +1577,7 @@ pub mod __internal {
 #[cfg(test)]
 mod tests {
     use super::{DomBuilder, text_signal, RefFn};
-    use crate::{html, shadow_root, ShadowRootMode, with_cfg};
+    use crate::{html, shadow_root, ShadowRootMode, with_cfg, intercept_links};
     use futures_signals::signal::{always, SignalExt};
     use once_cell::sync::Lazy;
     use web_sys::HtmlElement;
@@ -1734,4 +1734,11 @@ mod tests {
             })
         });
     }
+
+    #[test]
+    fn intercept_links() {
+        let _a = html!("div", {
+            .intercept_links!()
+        });
+    }
 }
@@ -65,12 +65,16 @@ macro_rules! make_event {
         impl $name {
             #[inline] pub fn prevent_default(&self) { self.event.prevent_default(); }
 
+            #[inline] pub fn default_prevented(&self) -> bool { self.event.default_prevented() }
+
             #[inline] pub fn stop_propagation(&self) { self.event.stop_propagation(); }
 
             #[inline] pub fn stop_immediate_propagation(&self) { self.event.stop_immediate_propagation(); }
 
             #[inline] pub fn target(&self) -> Option<EventTarget> { self.event.target() }
 
+            #[inline] pub fn current_target(&self) -> Option<EventTarget> { self.event.current_target() }
+
             #[inline]
             pub fn dyn_target<A>(&self) -> Option<A> where A: JsCast {
                 self.target()?.dyn_into().ok()
@@ -1,13 +1,18 @@
 use std::borrow::Cow;
+use std::future::Future;
+use std::sync::Once;
+use std::cell::RefCell;
 
-use web_sys::{EventTarget, HtmlElement};
+use wasm_bindgen::{JsCast, JsValue, UnwrapThrowExt};
+use web_sys::{EventTarget, Element, HtmlElement, Node, Url};
 use once_cell::sync::Lazy;
-use futures_signals::signal::{Mutable, ReadOnlyMutable};
+use futures_signals::signal::{Mutable, ReadOnlyMutable, SignalExt};
+use futures_util::stream::StreamExt;
 
 use crate::bindings;
 use crate::bindings::WINDOW;
 use crate::dom::{Dom, DomBuilder, EventOptions};
-use crate::utils::EventListener;
+use crate::utils::{EventListener, UnwrapJsExt};
 use crate::events;
 
 
@@ -25,27 +30,59 @@ fn change_url(mutable: &Mutable<String>) {
 }
 
 
-struct CurrentUrl {
-    value: Mutable<String>,
+thread_local! {
+    // The URL and history state after the last navigation
+    static LAST_NAVIGATION: RefCell<Option<(String, JsValue)>> = const { RefCell::new(None) };
 }
 
-impl CurrentUrl {
-    fn new() -> Self {
-        // TODO can this be made more efficient ?
-        let value = Mutable::new(String::from(bindings::current_url()));
+fn current_navigation() -> (String, JsValue) {
+    (bindings::current_url(), bindings::history_state())
+}
+
+// The popstate listener is shared by `url` and NAVIGATE_EVENT, so it is only created once
+fn listen_for_popstate() {
+    static LISTENING: Once = Once::new();
 
+    LISTENING.call_once(|| {
+        LAST_NAVIGATION.with(|last| {
+            last.borrow_mut().get_or_insert_with(current_navigation);
+        });
+
+        // Some browsers fire a spurious popstate on page load, so a popstate which doesn't change the
+        // URL or the history state is ignored. The history state is a new object after a real popstate,
+        // so this only ignores going back / forward between entries with the same URL and a `null` state.
         // TODO clean this up somehow ?
         let _ = WINDOW.with(|window| {
-            EventListener::new(window, "popstate", &EventOptions::default(), {
-                let value = value.clone();
-                move |_| {
-                    change_url(&value);
+            EventListener::new(window, "popstate", &EventOptions::default(), |_| {
+                let current = current_navigation();
+
+                let spurious = LAST_NAVIGATION.with(|last| {
+                    last.borrow().as_ref() == Some(&current)
+                });
+
+                if !spurious {
+                    navigated();
                 }
             })
         });
+    });
+}
+
+
+struct CurrentUrl {
+    value: Mutable<String>,
+    // This is incremented after every navigation, even if the URL stays the same
+    navigations: Mutable<usize>,
+}
+
+impl CurrentUrl {
+    fn new() -> Self {
+        listen_for_popstate();
 
         Self {
-            value,
+            // TODO can this be made more efficient ?
+            value: Mutable::new(String::from(bindings::current_url())),
+            navigations: Mutable::new(0),
         }
     }
 }
@@ -53,36 +90,385 @@ impl CurrentUrl {
 
 static URL: Lazy<CurrentUrl> = Lazy::new(|| CurrentUrl::new());
 
+// This is called after every navigation, including the back / forward buttons.
+fn navigated() {
+    // If the URL hasn't been created yet then there is nothing to update, because
+    // it reads the current URL when it is created. This means that navigating before
+    // the app has called `url` doesn't create it prematurely.
+    if let Some(url) = Lazy::get(&URL) {
+        change_url(&url.value);
+        url.navigations.replace_with(|x| *x + 1);
+    }
+
+    listen_for_popstate();
+
+    let current = current_navigation();
+
+    // This is dispatched after updating the URL, so that event listeners can use `url`
+    bindings::dispatch_window_event(NAVIGATE_EVENT, &JsValue::from(current.0.as_str()));
+
+    LAST_NAVIGATION.with(|last| {
+        *last.borrow_mut() = Some(current);
+    });
+}
 
+
+/// The name of the event which is dispatched on `window` after every navigation.
+///
+/// This includes [`go_to_url`], [`replace_url`], and the user navigating with the back / forward
+/// buttons, even if the app never uses [`url`]. Back / forward navigations are detected once the app
+/// has used any of [`url`], [`next_url`], [`go_to_url`], [`go_to_url_with_state`], [`replace_url`],
+/// or [`replace_url_with_state`] (this includes clicking a link which is handled by [`link!`](crate::link),
+/// [`on_click_go_to_url!`](crate::on_click_go_to_url), or [`intercept_links!`](crate::intercept_links)).
+///
+/// It is dispatched even if [`go_to_url`] or [`replace_url`] is called with the current URL.
+///
+/// It is not dispatched for the URL which the page was loaded with, even in browsers which fire a
+/// `popstate` event on page load.
+///
+/// The event's `detail` is the new URL. This allows for JavaScript code on the page
+/// (such as analytics scripts) to know when the app navigates:
+///
+/// ```js
+/// window.addEventListener("dominator:navigate", (e) => {
+///     console.log(e.detail);
+/// });
+/// ```
+pub const NAVIGATE_EVENT: &str = "dominator:navigate";
+
+
+/// The current full URL (normally the same as `window.location.href`).
+///
+/// It is automatically updated when calling [`go_to_url`] or [`replace_url`],
+/// and also when the user navigates with the back / forward buttons.
+///
+/// The exception is [`rewrite_url`], which deliberately changes `window.location.href`
+/// without updating this, so they differ until the next navigation.
+///
+/// ```rust
+/// html!("div", {
+///     .text_signal(routing::url().signal_cloned())
+/// })
+/// ```
 #[inline]
 pub fn url() -> ReadOnlyMutable<String> {
     URL.value.read_only()
 }
 
 
+/// Returns a `Future` which resolves with the new URL after the next navigation.
+///
+/// This is useful for flows which need to wait until the user navigates away:
+///
+/// ```rust
+/// let new_url = routing::next_url().await;
+/// ```
+///
+/// It resolves after [`go_to_url`], [`replace_url`], or the user navigating with the
+/// back / forward buttons, even if the new URL is the same as the old URL.
+///
+/// Navigations are counted from the time `next_url` was called, so navigations which happen
+/// before the `Future` is first polled are not missed. If there were several navigations,
+/// it resolves with the latest URL.
+pub fn next_url() -> impl Future<Output = String> {
+    let before = URL.navigations.get();
+
+    async move {
+        let mut navigations = URL.navigations.signal().to_stream();
+
+        loop {
+            // The URL is never dropped, so the Stream never ends
+            let count = navigations.next().await.unwrap_throw();
+
+            if count != before {
+                return URL.value.get_cloned();
+            }
+        }
+    }
+}
+
+
+// Returns the URL if it is on a different website, which can't be navigated to with the history API
+fn external_url(new_url: &str) -> Option<Url> {
+    let url = bindings::resolve_url(new_url)?;
+    let current = bindings::resolve_url(&bindings::current_url())?;
+
+    if is_loadable(&url) && !UrlParts::new(&current).can_push(&UrlParts::new(&url)) {
+        Some(url)
+
+    } else {
+        None
+    }
+}
+
+// Only these are allowed, so that loading an untrusted URL can't run JavaScript
+fn is_loadable(url: &Url) -> bool {
+    let protocol = url.protocol();
+    protocol == "http:" || protocol == "https:"
+}
+
 /// Update the current route by adding a new entry to the history.
-// TODO if URL hasn't been created yet, don't create it
+///
+/// If `new_url` is an `http:` or `https:` URL on a different website, the history API
+/// can't be used, so the new page is loaded instead (the same as [`load_url`]).
+///
+/// # Panics
+///
+/// If `new_url` can't be parsed, or if it isn't an `http:` or `https:` URL and it has
+/// a different origin than the current page (such as a `javascript:` URL).
 #[inline]
 #[track_caller]
 pub fn go_to_url(new_url: &str) {
-    // TODO intern ?
-    bindings::go_to_url(new_url);
+    go_to_url_with_state(new_url, &JsValue::NULL);
+}
+
+/// Same as [`go_to_url`], except it also stores `state` in the new history entry.
+///
+/// The state is kept by the browser, so it is still available after the user
+/// navigates back to this entry. Use [`history_state`] to read it.
+///
+/// If a new page is loaded, then `state` is not stored.
+///
+/// # Panics
+///
+/// In the same situations as [`go_to_url`], and also if `state` can't be
+/// [structured cloned](https://developer.mozilla.org/en-US/docs/Web/API/Web_Workers_API/Structured_clone_algorithm)
+/// (such as a function or a DOM node).
+#[inline]
+#[track_caller]
+pub fn go_to_url_with_state(new_url: &str, state: &JsValue) {
+    if let Some(url) = external_url(new_url) {
+        bindings::assign_location(&url.href());
+
+    } else {
+        // TODO intern ?
+        bindings::go_to_url(new_url, state);
 
-    change_url(&URL.value);
+        navigated();
+    }
 }
 
 /// Update the current route by replacing the history.
 /// Use this very sparingly as this break the back button.
 ///
 /// To let the user go back to the current route, use [`go_to_url`] instead.
-// TODO if URL hasn't been created yet, don't create it
+///
+/// If `new_url` is an `http:` or `https:` URL on a different website, the history API
+/// can't be used, so the new page is loaded instead, replacing the current page in
+/// the history (the same as `window.location.replace`).
+///
+/// # Panics
+///
+/// If `new_url` can't be parsed, or if it isn't an `http:` or `https:` URL and it has
+/// a different origin than the current page (such as a `javascript:` URL).
 #[inline]
 #[track_caller]
 pub fn replace_url(new_url: &str) {
-    // TODO intern ?
-    bindings::replace_url(new_url);
+    replace_url_with_state(new_url, &JsValue::NULL);
+}
+
+/// Same as [`replace_url`], except it also stores `state` in the current history entry.
+///
+/// Use [`history_state`] to read it.
+///
+/// If a new page is loaded, then `state` is not stored.
+///
+/// # Panics
+///
+/// In the same situations as [`replace_url`], and also if `state` can't be
+/// [structured cloned](https://developer.mozilla.org/en-US/docs/Web/API/Web_Workers_API/Structured_clone_algorithm)
+/// (such as a function or a DOM node).
+#[inline]
+#[track_caller]
+pub fn replace_url_with_state(new_url: &str, state: &JsValue) {
+    if let Some(url) = external_url(new_url) {
+        bindings::replace_location(&url.href());
+
+    } else {
+        // TODO intern ?
+        bindings::replace_url(new_url, state);
 
-    change_url(&URL.value);
+        navigated();
+    }
+}
+
+/// Navigates to `new_url` by loading a new page (the same as `window.location.assign`).
+///
+/// Unlike [`go_to_url`], this always loads a new page, even if `new_url` has the same origin as the current page.
+///
+/// Only `http:` and `https:` URLs are allowed, so that passing an untrusted URL
+/// (such as a `?next=` query parameter) cannot run a `javascript:` URL.
+///
+/// # Errors
+///
+/// If `new_url` is not a valid `http:` or `https:` URL, it returns an error and nothing happens:
+///
+/// ```rust
+/// if routing::load_url(&next).is_err() {
+///     routing::go_to_url("/");
+/// }
+/// ```
+#[track_caller]
+pub fn load_url(new_url: &str) -> Result<(), JsValue> {
+    let url = bindings::resolve_url(new_url)
+        .ok_or_else(|| js_sys::Error::new(&format!("Invalid URL: {}", new_url)))?;
+
+    if is_loadable(&url) {
+        bindings::assign_location(&url.href());
+        Ok(())
+
+    } else {
+        Err(js_sys::Error::new(&format!("Cannot load URL with protocol {}", url.protocol())).into())
+    }
+}
+
+/// Changes the URL which is displayed in the browser, without updating [`url`].
+///
+/// This replaces the current history entry (keeping its [`history_state`]), so it doesn't affect the back button.
+///
+/// It is intended for cosmetic changes where the logical route stays the same, such as
+/// canonicalizing the case of the URL, so that nothing needs to re-render.
+///
+/// Because [`url`] isn't updated, it will keep the old URL until the next navigation.
+/// This is also not a navigation, so it doesn't resolve [`next_url`].
+#[inline]
+#[track_caller]
+pub fn rewrite_url(new_url: &str) {
+    bindings::replace_url(new_url, &bindings::history_state());
+}
+
+/// Returns the state which is stored in the current history entry (the same as `window.history.state`).
+///
+/// This is `null` if the entry was not created with [`go_to_url_with_state`] or [`replace_url_with_state`].
+///
+/// The browser stores a [structured clone](https://developer.mozilla.org/en-US/docs/Web/API/Web_Workers_API/Structured_clone_algorithm)
+/// of the state, so it is not the same object which was passed in.
+///
+/// Because [`url`] is updated after the history entry changes, it is safe to call this
+/// when the [`url`] signal changes:
+///
+/// ```rust
+/// routing::url().signal_ref(|_| {
+///     let state = routing::history_state();
+///     // ...
+/// })
+/// ```
+///
+/// However, [`url`] only changes when the URL is different. If two history entries have the same URL
+/// but different states, going back / forward between them does not update [`url`], so the state change
+/// is not noticed. To observe it, give each entry a different URL (e.g. with a query parameter), or
+/// listen for [`NAVIGATE_EVENT`], which is dispatched after every navigation.
+#[inline]
+#[track_caller]
+pub fn history_state() -> JsValue {
+    bindings::history_state()
+}
+
+// The parts of a URL which are needed to decide whether the app can route to it
+#[derive(Debug)]
+struct UrlParts {
+    protocol: String,
+    username: String,
+    password: String,
+    host: String,
+    pathname: String,
+    search: String,
+    hash: String,
+}
+
+impl UrlParts {
+    fn new(url: &Url) -> Self {
+        let href = url.href();
+
+        Self {
+            protocol: url.protocol(),
+            username: url.username(),
+            password: url.password(),
+            host: url.host(),
+            pathname: url.pathname(),
+            search: url.search(),
+            // `Url::hash` is empty for `#`, but it is still a link to a fragment
+            hash: href.find('#').map(|index| String::from(&href[index..])).unwrap_or_default(),
+        }
+    }
+
+    // This is the same check that the history API uses, if it returns false then pushState / replaceState throw a SecurityError.
+    // It doesn't compare the origin, because `blob:` URLs and pages with an opaque origin (such as `file:` pages)
+    // have the same origin as URLs which can't be pushed.
+    fn can_push(&self, url: &Self) -> bool {
+        (self.protocol == "http:" || self.protocol == "https:") &&
+        url.protocol == self.protocol &&
+        url.username == self.username &&
+        url.password == self.password &&
+        url.host == self.host
+    }
+}
+
+// Returns whether a click on a link to `url` should be handled by the app instead of the browser
+fn is_routable_link(download: bool, target: Option<&str>, url: &UrlParts, current: &UrlParts) -> bool {
+    if download {
+        return false;
+    }
+
+    if let Some(target) = target {
+        if !target.is_empty() && target != "_self" {
+            return false;
+        }
+    }
+
+    if !current.can_push(url) {
+        return false;
+    }
+
+    // Links to a fragment on the current page are left to the browser, because it needs
+    // to scroll to the element, update `:target`, and fire `hashchange`
+    url.hash.is_empty() || url.pathname != current.pathname || url.search != current.search
+}
+
+// Returns the URL of the clicked `<a>`, but only if the click should be handled by the app.
+fn intercepted_url(e: &events::Click) -> Option<String> {
+    // The click was already handled, e.g. by on_click_go_to_url!
+    if e.default_prevented() {
+        return None;
+    }
+
+    // Ctrl+click, middle click, etc. open a new tab or window, so they are left to the browser
+    if e.button() != events::MouseButton::Left || e.ctrl_key() || e.shift_key() || e.alt_key() {
+        return None;
+    }
+
+    let link = e.dyn_target::<Element>()?.closest("a[href]").unwrap_js()?;
+
+    // Only handle links which are inside of the element that intercept_links! was used on
+    let root = e.current_target()?.dyn_into::<Node>().ok()?;
+
+    if !root.contains(Some(&link)) {
+        return None;
+    }
+
+    // Malformed URLs are left to the browser
+    let url = bindings::resolve_url(&link.get_attribute("href")?)?;
+    let current = bindings::resolve_url(&bindings::current_url())?;
+
+    let download = link.has_attribute("download");
+    let target = link.get_attribute("target");
+
+    if is_routable_link(download, target.as_deref(), &UrlParts::new(&url), &UrlParts::new(&current)) {
+        Some(url.href())
+
+    } else {
+        None
+    }
+}
+
+#[doc(hidden)]
+#[inline]
+#[track_caller]
+pub fn __intercept_link_click(e: events::Click) {
+    if let Some(url) = intercepted_url(&e) {
+        e.prevent_default();
+        go_to_url(&url);
+    }
 }
 
 #[deprecated(since = "0.5.1", note = "Use the on_click_go_to_url macro instead")]
@@ -182,3 +568,116 @@ macro_rules! link {
         })
     }};
 }
+
+
+/// Makes every `<a>` inside of an element work with routing.
+///
+/// This is useful for content which contains a lot of links, such as rendered Markdown,
+/// where it isn't possible to use the [`link!`](crate::link) macro for each link.
+///
+/// When the user clicks on an `<a href="...">` inside of the element, it will call
+/// [`go_to_url`] instead of letting the browser handle the URL routing.
+///
+/// The browser still handles the click if any of these are true:
+///
+/// * The URL can't be parsed, or it isn't an `http:` / `https:` URL with the same origin as the current page.
+/// * The URL only changes the fragment of the current page (e.g. `<a href="#foo">`).
+/// * The `<a>` has a `download` attribute, or a `target` attribute other than `_self`.
+/// * The user is holding down a modifier key, or isn't using the left mouse button.
+/// * The click was already handled (e.g. by [`on_click_go_to_url!`]).
+///
+/// ```rust
+/// html!("div", {
+///     .intercept_links!()
+///     .prop("innerHTML", "<a href=\"/my-url/foo\">Foo</a>")
+/// })
+/// ```
+#[macro_export]
+macro_rules! intercept_links {
+    ($this:ident $(,)?) => {{
+        $this.event_with_options(&$crate::EventOptions { bubbles: true, preventable: true }, $crate::routing::__intercept_link_click)
+    }};
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::{UrlParts, is_routable_link};
+
+    fn url(protocol: &str, host: &str, pathname: &str, search: &str, hash: &str) -> UrlParts {
+        UrlParts {
+            protocol: protocol.to_string(),
+            username: "".to_string(),
+            password: "".to_string(),
+            host: host.to_string(),
+            pathname: pathname.to_string(),
+            search: search.to_string(),
+            hash: hash.to_string(),
+        }
+    }
+
+    fn current() -> UrlParts {
+        url("https:", "example.com", "/foo", "?bar", "")
+    }
+
+    #[test]
+    fn routable_same_origin() {
+        assert!(is_routable_link(false, None, &url("https:", "example.com", "/qux", "", ""), &current()));
+        assert!(is_routable_link(false, None, &url("https:", "example.com", "/foo", "?bar", ""), &current()));
+        assert!(is_routable_link(false, None, &url("https:", "example.com", "/qux", "", "#corge"), &current()));
+    }
+
+    #[test]
+    fn routable_target() {
+        assert!(is_routable_link(false, Some(""), &url("https:", "example.com", "/qux", "", ""), &current()));
+        assert!(is_routable_link(false, Some("_self"), &url("https:", "example.com", "/qux", "", ""), &current()));
+        assert!(!is_routable_link(false, Some("_blank"), &url("https:", "example.com", "/qux", "", ""), &current()));
+        assert!(!is_routable_link(false, Some("foo"), &url("https:", "example.com", "/qux", "", ""), &current()));
+    }
+
+    #[test]
+    fn routable_download() {
+        assert!(!is_routable_link(true, None, &url("https:", "example.com", "/qux", "", ""), &current()));
+    }
+
+    #[test]
+    fn routable_different_origin() {
+        assert!(!is_routable_link(false, None, &url("https:", "example.org", "/qux", "", ""), &current()));
+        assert!(!is_routable_link(false, None, &url("https:", "example.com:8080", "/qux", "", ""), &current()));
+        assert!(!is_routable_link(false, None, &url("http:", "example.com", "/qux", "", ""), &current()));
+
+        let with_username = UrlParts {
+            username: "user".to_string(),
+            ..url("https:", "example.com", "/qux", "", "")
+        };
+
+        assert!(!is_routable_link(false, None, &with_username, &current()));
+    }
+
+    #[test]
+    fn routable_different_scheme() {
+        // blob: URLs have the same origin as the page which created them
+        assert!(!is_routable_link(false, None, &url("blob:", "", "https://example.com/1234", "", ""), &current()));
+        assert!(!is_routable_link(false, None, &url("mailto:", "", "foo@example.com", "", ""), &current()));
+        assert!(!is_routable_link(false, None, &url("javascript:", "", "void(0)", "", ""), &current()));
+    }
+
+    #[test]
+    fn routable_opaque_origin() {
+        // Every URL has a "null" origin on a file: page, so the protocol must be checked
+        let current = url("file:", "", "/foo.html", "", "");
+
+        assert!(!is_routable_link(false, None, &url("file:", "", "/qux.html", "", ""), &current));
+        assert!(!is_routable_link(false, None, &url("mailto:", "", "foo@example.com", "", ""), &current));
+        assert!(!is_routable_link(false, None, &url("tel:", "", "+1234", "", ""), &current));
+        assert!(!is_routable_link(false, None, &url("javascript:", "", "void(0)", "", ""), &current));
+    }
+
+    #[test]
+    fn routable_fragment() {
+        assert!(!is_routable_link(false, None, &url("https:", "example.com", "/foo", "?bar", "#"), &current()));
+        assert!(!is_routable_link(false, None, &url("https:", "example.com", "/foo", "?bar", "#qux"), &current()));
+        assert!(is_routable_link(false, None, &url("https:", "example.com", "/foo", "", "#qux"), &current()));
+        assert!(is_routable_link(false, None, &url("https:", "example.com", "/qux", "?bar", "#qux"), &current()));
+    }
+}